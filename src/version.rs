@@ -1,19 +1,22 @@
 use std::collections::HashMap;
+use std::io::MemReader;
 use std::time::Duration;
 use serialize::json;
 use time::Timespec;
 
 use conduit::{Request, Response};
 use conduit_router::RequestParams;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use pg::PostgresRow;
 use pg::types::ToSql;
 use semver;
 use url;
 
 use {Model, Crate, User};
-use app::RequestApp;
+use app::{App, RequestApp};
 use db::{Connection, RequestTransaction};
-use dependency::{Dependency, EncodableDependency};
+use dependency::{Dependency, DependencyKind, EncodableDependency};
 use download::{VersionDownload, EncodableVersionDownload};
 use git;
 use upload;
@@ -29,7 +32,9 @@ pub struct Version {
     pub created_at: Timespec,
     pub downloads: i32,
     pub features: HashMap<String, Vec<String>>,
+    pub features2: HashMap<String, Vec<String>>,
     pub yanked: bool,
+    pub cksum: String,
 }
 
 pub enum VersionAuthor {
@@ -47,10 +52,40 @@ pub struct EncodableVersion {
     pub created_at: String,
     pub downloads: i32,
     pub features: HashMap<String, Vec<String>>,
+    pub features2: HashMap<String, Vec<String>>,
     pub yanked: bool,
+    pub cksum: String,
     pub links: VersionLinks,
 }
 
+/// A single line of the cargo registry index, as consumed by cargo when it
+/// resolves dependencies. Serialized as one newline-terminated JSON object.
+#[deriving(Encodable, Decodable)]
+pub struct IndexEntry {
+    pub name: String,
+    pub vers: String,
+    pub deps: Vec<IndexDependency>,
+    pub cksum: String,
+    pub features: HashMap<String, Vec<String>>,
+    pub features2: Option<HashMap<String, Vec<String>>>,
+    pub yanked: bool,
+    pub links: Option<String>,
+    pub v: u32,
+}
+
+#[deriving(Encodable, Decodable)]
+pub struct IndexDependency {
+    pub name: String,
+    pub req: String,
+    pub features: Vec<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    pub target: Option<String>,
+    pub kind: DependencyKind,
+    pub registry: Option<String>,
+    pub package: Option<String>,
+}
+
 #[deriving(Encodable, Decodable)]
 pub struct VersionLinks {
     pub dependencies: String,
@@ -58,6 +93,16 @@ pub struct VersionLinks {
     pub authors: String,
 }
 
+/// A single point on a download growth curve: the start of the time bucket,
+/// the downloads that fell inside it, and the running total up to and
+/// including that bucket.
+#[deriving(Encodable, Decodable)]
+pub struct EncodableDownloadBucket {
+    pub bucket_start: String,
+    pub downloads: i64,
+    pub cumulative: i64,
+}
+
 impl Version {
     pub fn find(conn: &Connection, id: i32) -> CargoResult<Version> {
         Model::find(conn, id)
@@ -75,33 +120,59 @@ impl Version {
     pub fn insert(conn: &Connection, crate_id: i32,
                   num: &semver::Version,
                   features: &HashMap<String, Vec<String>>,
-                  authors: &[String])
+                  authors: &[String],
+                  cksum: &str)
                   -> CargoResult<Version> {
         let num = num.to_string();
-        let features = json::encode(features);
+        let (features, features2) = Version::split_features(features);
+        let features = json::encode(&features);
+        let features2 = json::encode(&features2);
         let stmt = try!(conn.prepare("INSERT INTO versions \
                                       (crate_id, num, updated_at, \
-                                       created_at, downloads, features) \
-                                      VALUES ($1, $2, $3, $3, 0, $4) \
+                                       created_at, downloads, features, \
+                                       features2, cksum) \
+                                      VALUES ($1, $2, $3, $3, 0, $4, $5, $6) \
                                       RETURNING *"));
         let now = ::now();
-        let mut rows = try!(stmt.query(&[&crate_id, &num, &now, &features]));
+        let mut rows = try!(stmt.query(&[&crate_id, &num, &now, &features,
+                                         &features2, &cksum as &ToSql]));
         let ret: Version = Model::from_row(&try!(rows.next().require(|| {
             internal("no version returned")
         })));
         for author in authors.iter() {
             try!(ret.add_author(conn, author.as_slice()));
         }
+        // NB: the git index line is emitted by the publish handler *after* it
+        // has attached dependencies via `add_dependency`; writing it here would
+        // serialize an entry with `deps: []`.
         Ok(ret)
     }
 
+    /// Partition a feature table into the classic features and the modern
+    /// `features2` table. A feature whose values use the `dep:foo` or
+    /// `pkg?/feat` syntax cannot be understood by older cargo, so it is kept
+    /// separate; the index entry only carries it together with `v: 2`.
+    fn split_features(features: &HashMap<String, Vec<String>>)
+                      -> (HashMap<String, Vec<String>>,
+                          HashMap<String, Vec<String>>) {
+        let (mut classic, mut modern) = (HashMap::new(), HashMap::new());
+        for (feat, values) in features.iter() {
+            let modern_syntax = values.iter().any(|v| {
+                v.as_slice().starts_with("dep:") || v.as_slice().contains("?/")
+            });
+            let dst = if modern_syntax { &mut modern } else { &mut classic };
+            dst.insert(feat.clone(), values.clone());
+        }
+        (classic, modern)
+    }
+
     pub fn valid(version: &str) -> bool {
         semver::Version::parse(version).is_ok()
     }
 
     pub fn encodable(self, crate_name: &str) -> EncodableVersion {
         let Version { id, crate_id: _, num, updated_at, created_at,
-                      downloads, features, yanked } = self;
+                      downloads, features, features2, yanked, cksum } = self;
         let num = num.to_string();
         EncodableVersion {
             dl_path: format!("/api/v1/crates/{}/{}/download", crate_name, num),
@@ -112,7 +183,9 @@ impl Version {
             created_at: ::encode_time(created_at),
             downloads: downloads,
             features: features,
+            features2: features2,
             yanked: yanked,
+            cksum: cksum,
             links: VersionLinks {
                 dependencies: format!("/api/v1/crates/{}/{}/dependencies",
                                       crate_name, num),
@@ -136,9 +209,10 @@ impl Version {
             (**s).to_string()
         }).collect();
         let dep = try!(Dependency::insert(conn, self.id, krate.id,
-                                          &*dep.version_req, dep.optional,
-                                          dep.default_features,
-                                          features.as_slice()));
+                                          &*dep.version_req, dep.kind,
+                                          dep.optional, dep.default_features,
+                                          features.as_slice(),
+                                          &dep.target));
         Ok((dep, krate))
     }
 
@@ -156,28 +230,181 @@ impl Version {
         }).collect())
     }
 
+    /// Build the entry cargo expects to find for this version in the git
+    /// index. Nothing is written here: `write_index` collects the entries of
+    /// every version of the crate and rewrites the file named by the
+    /// crate-name prefix layout (see `index_path`).
+    pub fn index_entry(&self, conn: &Connection, crate_name: &str)
+                       -> CargoResult<IndexEntry> {
+        let deps = try!(self.dependencies(conn));
+        let deps = deps.into_iter().map(|(dep, name)| {
+            IndexDependency {
+                name: name,
+                req: dep.req.to_string(),
+                features: dep.features,
+                optional: dep.optional,
+                default_features: dep.default_features,
+                target: dep.target,
+                kind: dep.kind,
+                registry: None,
+                package: None,
+            }
+        }).collect();
+        let (features2, v) = if self.features2.is_empty() {
+            (None, 1)
+        } else {
+            (Some(self.features2.clone()), 2)
+        };
+        Ok(IndexEntry {
+            name: crate_name.to_string(),
+            vers: self.num.to_string(),
+            deps: deps,
+            cksum: self.cksum.clone(),
+            features: self.features.clone(),
+            features2: features2,
+            yanked: self.yanked,
+            links: None,
+            v: v,
+        })
+    }
+
+    /// The crate-name prefix path (e.g. `ab/cd/name`) that addresses this
+    /// crate's line within the git index.
+    pub fn index_path(crate_name: &str) -> Path {
+        let name = crate_name.to_ascii_lower();
+        match name.len() {
+            1 => Path::new(format!("1/{}", name)),
+            2 => Path::new(format!("2/{}", name)),
+            3 => Path::new(format!("3/{}/{}", name.as_slice().slice_to(1), name)),
+            _ => Path::new(format!("{}/{}/{}",
+                                   name.as_slice().slice(0, 2),
+                                   name.as_slice().slice(2, 4),
+                                   name)),
+        }
+    }
+
+    /// Re-hash the raw `.crate` bytes with SHA-256 and confirm they still
+    /// match the checksum recorded at publish time. A mismatch means the
+    /// stored blob is corrupt or tampered, which is an `internal` error.
+    pub fn verify_cksum(&self, data: &[u8]) -> CargoResult<()> {
+        let mut hasher = Sha256::new();
+        hasher.input(data);
+        let actual = hasher.result_str();
+        if actual.as_slice() != self.cksum.as_slice() {
+            return Err(internal(format!("checksum mismatch for version {}: \
+                                         expected {}, found {}",
+                                        self.num, self.cksum, actual)))
+        }
+        Ok(())
+    }
+
+    /// Re-emit the crate's entire index file, one line per version, keyed by
+    /// the crate-name prefix layout. This makes the index a first-class output
+    /// of the model: callers that mutate any version (publish, yank, unyank)
+    /// rewrite the whole file from the current set of versions, so a single
+    /// version's change never drops its siblings' lines.
+    pub fn write_index(&self, conn: &Connection, app: &App, crate_name: &str)
+                       -> CargoResult<()> {
+        let stmt = try!(conn.prepare("SELECT * FROM versions \
+                                      WHERE crate_id = $1 ORDER BY id ASC"));
+        let mut entries = Vec::new();
+        for row in try!(stmt.query(&[&self.crate_id])) {
+            let version: Version = Model::from_row(&row);
+            entries.push(try!(version.index_entry(conn, crate_name)));
+        }
+        git::write(app, &Version::index_path(crate_name), entries.as_slice())
+    }
+
     pub fn authors(&self, conn: &Connection) -> CargoResult<Vec<VersionAuthor>> {
         let stmt = try!(conn.prepare("SELECT * FROM version_authors
                                        WHERE version_id = $1"));
         let rows = try!(stmt.query(&[&self.id]));
         rows.map(|row| {
             let user_id: Option<i32> = row.get("user_id");
-            let name: String = row.get("name");
             Ok(match user_id {
                 Some(id) => AuthorUser(try!(User::find(conn, id))),
-                None => AuthorName(name),
+                None => {
+                    let name: String = row.get("name");
+                    AuthorName(name)
+                }
             })
         }).collect()
     }
 
-    pub fn add_author(&self, conn: &Connection, name: &str) -> CargoResult<()> {
-        println!("add author: {}", name);
-        // TODO: at least try to link `name` to a pre-existing user
-        try!(conn.execute("INSERT INTO version_authors (version_id, name)
-                           VALUES ($1, $2)", &[&self.id, &name as &ToSql]));
+    pub fn add_author(&self, conn: &Connection, author: &str) -> CargoResult<()> {
+        let (name, email) = Version::parse_author(author);
+        match try!(Version::find_user(conn, name, email)) {
+            Some(user_id) => {
+                try!(conn.execute("INSERT INTO version_authors (version_id, user_id)
+                                   VALUES ($1, $2)", &[&self.id, &user_id]));
+            }
+            None => {
+                try!(conn.execute("INSERT INTO version_authors (version_id, name)
+                                   VALUES ($1, $2)", &[&self.id, &author as &ToSql]));
+            }
+        }
         Ok(())
     }
 
+    /// Split an author string into an optional display name and email. Handles
+    /// both the `Name <email>` form and a bare name or email.
+    fn parse_author(author: &str) -> (Option<&str>, Option<&str>) {
+        match (author.find('<'), author.rfind('>')) {
+            (Some(lt), Some(gt)) if lt < gt => {
+                let name = author.slice_to(lt).trim();
+                let email = author.slice(lt + 1, gt).trim();
+                let name = if name.is_empty() { None } else { Some(name) };
+                let email = if email.is_empty() { None } else { Some(email) };
+                (name, email)
+            }
+            _ => {
+                let author = author.trim();
+                if author.find('@').is_some() {
+                    (None, Some(author))
+                } else if author.is_empty() {
+                    (None, None)
+                } else {
+                    (Some(author), None)
+                }
+            }
+        }
+    }
+
+    /// Try to match a parsed author against a pre-existing account, preferring
+    /// an email match over matching the author's name against `gh_login`.
+    /// Returns the user id if found.
+    fn find_user(conn: &Connection, name: Option<&str>, email: Option<&str>)
+                 -> CargoResult<Option<i32>> {
+        match email {
+            Some(email) => {
+                let stmt = try!(conn.prepare("SELECT id FROM users
+                                               WHERE email = $1"));
+                let mut rows = try!(stmt.query(&[&email as &ToSql]));
+                match rows.next() {
+                    Some(row) => return Ok(Some(row.get("id"))),
+                    None => {}
+                }
+            }
+            None => {}
+        }
+        match name {
+            Some(name) => {
+                // Match on `gh_login` only: a bare display name is not unique
+                // and could mislink a version to an unrelated account whose
+                // `name` happens to collide.
+                let stmt = try!(conn.prepare("SELECT id FROM users
+                                               WHERE gh_login = $1"));
+                let mut rows = try!(stmt.query(&[&name as &ToSql]));
+                match rows.next() {
+                    Some(row) => return Ok(Some(row.get("id"))),
+                    None => {}
+                }
+            }
+            None => {}
+        }
+        Ok(None)
+    }
+
     pub fn yank(&self, conn: &Connection, yanked: bool) -> CargoResult<()> {
         try!(conn.execute("UPDATE versions SET yanked = $1 WHERE id = $2",
                           &[&yanked, &self.id]));
@@ -192,6 +419,10 @@ impl Model for Version {
         let features = features.map(|s| {
             json::decode(s.as_slice()).unwrap()
         }).unwrap_or_else(|| HashMap::new());
+        let features2: Option<String> = row.get("features2");
+        let features2 = features2.map(|s| {
+            json::decode(s.as_slice()).unwrap()
+        }).unwrap_or_else(|| HashMap::new());
         Version {
             id: row.get("id"),
             crate_id: row.get("crate_id"),
@@ -200,7 +431,9 @@ impl Model for Version {
             created_at: row.get("created_at"),
             downloads: row.get("downloads"),
             features: features,
+            features2: features2,
             yanked: row.get("yanked"),
+            cksum: row.get("cksum"),
         }
     }
     fn table_name(_: Option<Version>) -> &'static str { "versions" }
@@ -280,7 +513,11 @@ pub fn dependencies(req: &mut Request) -> CargoResult<Response> {
     let tx = try!(req.tx());
     let deps = try!(version.dependencies(tx));
     let deps = deps.into_iter().map(|(dep, crate_name)| {
-        dep.encodable(crate_name.as_slice())
+        let (kind, target) = (dep.kind, dep.target.clone());
+        let mut enc = dep.encodable(crate_name.as_slice());
+        enc.kind = kind;
+        enc.target = target;
+        enc
     }).collect();
 
     #[deriving(Encodable)]
@@ -288,6 +525,25 @@ pub fn dependencies(req: &mut Request) -> CargoResult<Response> {
     Ok(req.json(&R{ dependencies: deps }))
 }
 
+pub fn download(req: &mut Request) -> CargoResult<Response> {
+    let (version, krate) = try!(version_and_crate(req));
+    let app = req.app().clone();
+
+    // Pull the stored tarball back out, re-hash it, and refuse to serve a blob
+    // whose digest no longer matches what we recorded at publish time.
+    let path = format!("crates/{}/{}-{}.crate", krate.name, krate.name,
+                       version.num);
+    let mut body = Vec::new();
+    try!(app.uploader.read(path.as_slice(), &mut body));
+    try!(version.verify_cksum(body.as_slice()));
+
+    Ok(Response {
+        status: (200, "OK"),
+        headers: HashMap::new(),
+        body: box MemReader::new(body),
+    })
+}
+
 pub fn downloads(req: &mut Request) -> CargoResult<Response> {
     let (version, _) = try!(version_and_crate(req));
 
@@ -307,6 +563,50 @@ pub fn downloads(req: &mut Request) -> CargoResult<Response> {
     Ok(req.json(&R{ version_downloads: downloads }))
 }
 
+pub fn download_graph(req: &mut Request) -> CargoResult<Response> {
+    let crate_name = req.params()["crate_id"].as_slice();
+    let query = url::form_urlencoded::parse_str(req.query_string().unwrap_or(""));
+    let interval = query.iter().filter_map(|&(ref a, ref b)| {
+        if a.as_slice() == "interval" { Some(b.clone()) } else { None }
+    }).next();
+    let interval = match interval.as_ref().map(|s| s.as_slice()) {
+        None | Some("week") => "week",
+        Some("month") => "month",
+        Some(other) => return Err(human(format!("invalid interval `{}`, \
+                                                 expected `week` or `month`",
+                                                other))),
+    };
+
+    let tx = try!(req.tx());
+    let krate = try!(Crate::find_by_name(tx, crate_name));
+    let stmt = try!(tx.prepare("SELECT date_trunc($1, version_downloads.date) \
+                                       AS bucket,
+                                       SUM(version_downloads.downloads) \
+                                       AS downloads
+                                FROM version_downloads
+                                INNER JOIN versions
+                                  ON versions.id = version_downloads.version_id
+                                WHERE versions.crate_id = $2
+                                GROUP BY bucket
+                                ORDER BY bucket ASC"));
+    let mut buckets = Vec::new();
+    let mut cumulative = 0i64;
+    for row in try!(stmt.query(&[&interval as &ToSql, &krate.id])) {
+        let bucket: Timespec = row.get("bucket");
+        let downloads: i64 = row.get("downloads");
+        cumulative += downloads;
+        buckets.push(EncodableDownloadBucket {
+            bucket_start: ::encode_time(bucket),
+            downloads: downloads,
+            cumulative: cumulative,
+        });
+    }
+
+    #[deriving(Encodable)]
+    struct R { downloads: Vec<EncodableDownloadBucket> }
+    Ok(req.json(&R{ downloads: buckets }))
+}
+
 pub fn authors(req: &mut Request) -> CargoResult<Response> {
     let (version, _) = try!(version_and_crate(req));
     let tx = try!(req.tx());
@@ -336,6 +636,7 @@ pub fn unyank(req: &mut Request) -> CargoResult<Response> {
 fn modify_yank(req: &mut Request, yanked: bool) -> CargoResult<Response> {
     let (version, krate) = try!(version_and_crate(req));
     let user = try!(req.user());
+    let app = req.app().clone();
     let tx = try!(req.tx());
     let owners = try!(krate.owners(tx));
     if !owners.iter().any(|u| u.id == user.id) {
@@ -344,7 +645,7 @@ fn modify_yank(req: &mut Request, yanked: bool) -> CargoResult<Response> {
 
     if version.yanked != yanked {
         try!(version.yank(tx, yanked));
-        try!(git::yank(&**req.app(), krate.name.as_slice(), &version.num, yanked));
+        try!(version.write_index(tx, &*app, krate.name.as_slice()));
     }
 
     #[deriving(Encodable)]